@@ -1,14 +1,52 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
 use cpal::{
     platform::Host, traits::{DeviceTrait, HostTrait, StreamTrait}
 };
 
 use ringbuf::HeapRb;
 
+/// Controls how the consumer bridges mismatched input/output sample rates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResampleQuality {
+    /// Pick the single nearest input sample (cheapest, lowest fidelity).
+    Nearest,
+    /// Linearly interpolate between the two bracketing input samples.
+    Linear,
+}
+
+/// Where the loopback's input signal comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputSource {
+    /// Capture from `Loopback::input_device`, as usual.
+    Device,
+    /// A sine wave at a fixed frequency (Hz).
+    Sine { freq: f32 },
+    /// Uniform white noise in `[-1.0, 1.0]`.
+    WhiteNoise,
+    /// A sine wave that linearly sweeps from `start` to `end` Hz over
+    /// `secs` seconds, then holds at `end`.
+    Sweep { start: f32, end: f32, secs: f32 },
+}
+
 pub struct Loopback<'a> {
     pub host: Host,
     pub input_device: &'a str,
     pub output_device: &'a str,
     pub latency: f32,
+    pub resample_quality: ResampleQuality,
+    /// When set, the captured (pre-output) signal is also written to this
+    /// path as a WAV file for the lifetime of the loopback.
+    pub record_to: Option<PathBuf>,
+    /// Where the input signal comes from. Defaults to `InputSource::Device`.
+    pub input_source: InputSource,
+    /// The DSP chain applied to the captured signal before it reaches the
+    /// ring buffer, in order. Empty by default; push stages onto it
+    /// directly, e.g. `loopback.processors.push(Box::new(Gain { gain: 2.0 }))`.
+    pub processors: Vec<Box<dyn Processor + Send>>,
 }
 
 impl Loopback<'_> {
@@ -18,21 +56,27 @@ impl Loopback<'_> {
             input_device: "default",
             output_device: "default",
             latency: 150.0,
+            resample_quality: ResampleQuality::Linear,
+            record_to: None,
+            input_source: InputSource::Device,
+            processors: Vec::new(),
         }
     }
 
-    pub fn start(&mut self) -> anyhow::Result<()> {
+    /// Appends a DSP stage to the processing chain and returns `self`, for
+    /// building up a `Loopback` in a single expression. Stages run in the
+    /// order they were added, on the captured signal before it reaches the
+    /// ring buffer. Equivalent to `self.processors.push(Box::new(processor))`,
+    /// which is also fine to call directly once `self` is bound to a
+    /// variable.
+    pub fn with_processor(mut self, processor: impl Processor + Send + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    pub fn start(&mut self) -> anyhow::Result<LoopbackHandle> {
             let host = &self.host;
-        
-            // Find devices.
-            let input_device = if &self.input_device == &"default" {
-                host.default_input_device()
-            } else {
-                host.input_devices()?
-                    .find(|x| x.name().map(|y| &stringify!(y) == &self.input_device).unwrap_or(false))
-            }
-            .expect("Failed to find input device!");
-        
+
             let output_device = if &self.output_device == &"default" {
                 host.default_output_device()
             } else {
@@ -40,84 +84,920 @@ impl Loopback<'_> {
                     .find(|x| x.name().map(|y| &stringify!(y) == &self.output_device).unwrap_or(false))
             }
             .expect("Failed to find output device!");
-        
-            println!("Using input device: \"{}\"", input_device.name()?);
+
             println!("Using output device: \"{}\"", output_device.name()?);
-        
-            // We'll try and use the same configuration between streams to keep it simple.
-            let config: cpal::StreamConfig = input_device.default_input_config()?.into();
-        
+
+            let output_supported_config = output_device.default_output_config()?;
+            let output_sample_format = output_supported_config.sample_format();
+            let output_config: cpal::StreamConfig = output_supported_config.into();
+            let out_rate = output_config.sample_rate.0 as f64;
+            let out_channels = output_config.channels as usize;
+
+            // `InputSource::Device` captures from real hardware; the other
+            // variants synthesize their own signal and never touch
+            // `host.input_devices()`. Either way they feed the same
+            // processing chain, so they share `in_rate`/`in_channels`
+            // downstream. The two real devices are queried independently
+            // because they may not agree on sample rate, channel count, or
+            // sample format, e.g. a 48kHz stereo I16 mic feeding a 44.1kHz
+            // mono F32 output.
+            let mut input_device = None;
+            let mut input_sample_format = None;
+            let mut input_config = None;
+
+            if matches!(self.input_source, InputSource::Device) {
+                let device = if &self.input_device == &"default" {
+                    host.default_input_device()
+                } else {
+                    host.input_devices()?
+                        .find(|x| x.name().map(|y| &stringify!(y) == &self.input_device).unwrap_or(false))
+                }
+                .expect("Failed to find input device!");
+
+                println!("Using input device: \"{}\"", device.name()?);
+
+                let supported_config = device.default_input_config()?;
+                input_sample_format = Some(supported_config.sample_format());
+                input_config = Some(cpal::StreamConfig::from(supported_config));
+                input_device = Some(device);
+            } else {
+                println!("Using synthesized input source: {:?}", self.input_source);
+            }
+
+            let in_rate = input_config.as_ref().map_or(out_rate, |c: &cpal::StreamConfig| c.sample_rate.0 as f64);
+            let in_channels = input_config.as_ref().map_or(out_channels, |c| c.channels as usize);
+            let input_sample_rate = input_config.as_ref().map_or(output_config.sample_rate.0, |c| c.sample_rate.0);
+
             // Create a delay in case the input and output devices aren't synced.
-            let latency_frames = (&self.latency / 1_000.0) * config.sample_rate.0 as f32;
-            let latency_samples = latency_frames as usize * config.channels as usize;
-        
+            // Latency is measured in input frames, since the ring buffer holds
+            // raw (not yet resampled) input samples.
+            let latency_frames = (&self.latency / 1_000.0) * input_sample_rate as f32;
+            let latency_samples = latency_frames as usize * in_channels;
+
             // The buffer to share samples
             let ring = HeapRb::<f32>::new(latency_samples * 2);
             let (mut producer, mut consumer) = ring.split();
-        
+
             // Fill the samples with 0.0 equal to the length of the delay.
             for _ in 0..latency_samples {
                 // The ring buffer has twice as much space as necessary to add latency here,
                 // so this should never fail
                 producer.push(0.0).unwrap();
             }
-        
-            let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                let mut output_fell_behind = false;
-                for &sample in data {
-                    if producer.push(sample).is_err() {
-                        output_fell_behind = true;
-                    }
-                }
-                if output_fell_behind {
-                    eprintln!("Output stream fell behind! Try increasing latency.");
-                }
-            };
-        
-            let output_data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                let mut input_fell_behind = false;
-                for sample in data {
-                    *sample = match consumer.pop() {
-                        Some(s) => s,
-                        None => {
-                            input_fell_behind = true;
-                            0.0
-                        }
+
+            let processors = std::mem::take(&mut self.processors);
+            let resampler = Resampler::new(self.resample_quality, in_rate, out_rate, in_channels, out_channels);
+            let counters = Arc::new(XrunCounters::default());
+
+            // Recording, if requested, taps the captured signal through its
+            // own ring buffer so file I/O never runs on the audio thread.
+            let (record_producer, recorder) = match &self.record_to {
+                Some(path) => {
+                    // `channels`/`sample_rate` are derived from the capture
+                    // side, but `bits_per_sample`/`sample_format` are
+                    // intentionally always 32-bit float rather than matching
+                    // the input device's bit depth (e.g. I16): the recorder
+                    // taps the signal *after* it's been converted to f32 and
+                    // run through the processor chain (the same samples fed
+                    // to the ring buffer), so the file always holds real
+                    // f32 values regardless of capture format -- writing it
+                    // as, say, 16-bit would either mismatch the sample type
+                    // actually being written or require re-quantizing a
+                    // signal that's already been processed in float.
+                    let spec = hound::WavSpec {
+                        channels: in_channels as u16,
+                        sample_rate: input_sample_rate,
+                        bits_per_sample: 32,
+                        sample_format: hound::SampleFormat::Float,
                     };
+                    let record_ring = HeapRb::<f32>::new(latency_samples * 2);
+                    let (record_producer, record_consumer) = record_ring.split();
+                    let recorder = Recorder::spawn(path.clone(), spec, record_consumer);
+                    (Some(record_producer), Some(recorder))
                 }
-                if input_fell_behind {
-                    eprintln!("Input stream fell behind! Try increasing latency.");
-                }
+                None => (None, None),
+            };
+
+            // Build streams. The sample format isn't known until runtime, so
+            // each branch monomorphizes the generic builder for its format;
+            // everything past capture/before playback stays in f32.
+            let (input_stream, generator) = if let (Some(device), Some(config), Some(format)) =
+                (&input_device, &input_config, input_sample_format)
+            {
+                println!(
+                    "Attempting to build input stream with {:?} samples and `{:?}`.",
+                    format, config
+                );
+                let stream = match format {
+                    cpal::SampleFormat::I16 => build_input_stream::<i16>(device, config, producer, processors, in_channels, input_sample_rate, counters.clone(), record_producer)?,
+                    cpal::SampleFormat::U16 => build_input_stream::<u16>(device, config, producer, processors, in_channels, input_sample_rate, counters.clone(), record_producer)?,
+                    cpal::SampleFormat::F32 => build_input_stream::<f32>(device, config, producer, processors, in_channels, input_sample_rate, counters.clone(), record_producer)?,
+                    sample_format => anyhow::bail!("Unsupported input sample format '{sample_format}'"),
+                };
+                (Some(stream), None)
+            } else {
+                println!(
+                    "Synthesizing input at {} Hz / {} channel(s) instead of building an input stream.",
+                    input_sample_rate, in_channels
+                );
+                let generator = Generator::spawn(self.input_source, in_channels, input_sample_rate, producer, processors, record_producer, counters.clone());
+                (None, Some(generator))
             };
-        
-            // Build streams.
+
             println!(
-                "Attempting to build both streams with f32 samples and `{:?}`.",
-                config
+                "Attempting to build output stream with {:?} samples and `{:?}`.",
+                output_sample_format, output_config
             );
-            let input_stream = input_device.build_input_stream(&config, input_data_fn, err_fn, None)?;
-            let output_stream = output_device.build_output_stream(&config, output_data_fn, err_fn, None)?;
+            let output_stream = match output_sample_format {
+                cpal::SampleFormat::I16 => build_output_stream::<i16>(&output_device, &output_config, consumer, resampler, out_channels, counters.clone())?,
+                cpal::SampleFormat::U16 => build_output_stream::<u16>(&output_device, &output_config, consumer, resampler, out_channels, counters.clone())?,
+                cpal::SampleFormat::F32 => build_output_stream::<f32>(&output_device, &output_config, consumer, resampler, out_channels, counters.clone())?,
+                sample_format => anyhow::bail!("Unsupported output sample format '{sample_format}'"),
+            };
             println!("Successfully built streams.");
-        
+
             // Play the streams.
             println!(
                 "Starting the input and output streams with `{}` milliseconds of latency.",
                 &self.latency
             );
-            input_stream.play()?;
+            if let Some(stream) = &input_stream {
+                stream.play()?;
+            }
             output_stream.play()?;
-        
-            // Run for 3 seconds before closing.
-            println!("Playing for 3 seconds... ");
-            std::thread::sleep(std::time::Duration::from_secs(3));
-            drop(input_stream);
-            drop(output_stream);
-            println!("Done!");
-            Ok(())
+
+            Ok(LoopbackHandle {
+                input_stream,
+                output_stream: Some(output_stream),
+                counters,
+                recorder,
+                generator,
+            })
+    }
+}
+
+/// Underrun/overrun counts for a running [`LoopbackHandle`], as read from
+/// [`LoopbackHandle::xruns`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XrunCounts {
+    /// Samples dropped because the producer (capture side) filled the ring
+    /// buffer faster than the consumer drained it.
+    pub output_overruns: u64,
+    /// Output frames that had to be filled with silence because the
+    /// consumer (playback side) drained the ring buffer faster than the
+    /// producer filled it.
+    pub input_underruns: u64,
+}
+
+/// The atomics backing [`XrunCounts`], shared with the stream callbacks so
+/// they can record xruns without doing any I/O of their own.
+#[derive(Default)]
+struct XrunCounters {
+    output_overruns: AtomicU64,
+    input_underruns: AtomicU64,
+}
+
+/// Drives the background WAV-writer thread started when
+/// [`Loopback::record_to`] is set. File I/O must never happen on the audio
+/// callback, so the callback only pushes into a lock-free ring and this
+/// thread does the blocking `hound` writes.
+struct Recorder {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Recorder {
+    fn spawn(path: PathBuf, spec: hound::WavSpec, mut consumer: ringbuf::HeapConsumer<f32>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        let thread = std::thread::spawn(move || {
+            let mut writer = match hound::WavWriter::create(&path, spec) {
+                Ok(writer) => writer,
+                Err(err) => {
+                    eprintln!("Failed to create WAV file {:?}: {}", path, err);
+                    return;
+                }
+            };
+            loop {
+                match consumer.pop() {
+                    Some(sample) => {
+                        if let Err(err) = writer.write_sample(sample) {
+                            eprintln!("Failed to write WAV sample: {}", err);
+                        }
+                    }
+                    None if stop_thread.load(Ordering::Relaxed) => break,
+                    None => std::thread::sleep(Duration::from_millis(5)),
+                }
+            }
+            if let Err(err) = writer.finalize() {
+                eprintln!("Failed to finalize WAV file: {}", err);
+            }
+        });
+        Recorder { stop, thread: Some(thread) }
     }
 }
 
+impl Drop for Recorder {
+    /// Signals the writer thread to drain the remaining buffered samples,
+    /// finalize the WAV header, and exit. Runs whether the recorder is
+    /// dropped explicitly (via `LoopbackHandle::stop`) or implicitly (early
+    /// return, panic unwind, or simply letting the handle go out of scope),
+    /// so the WAV file is always left in a readable state.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if let Err(err) = thread.join() {
+                eprintln!("Recorder thread panicked: {:?}", err);
+            }
+        }
+    }
+}
+
+/// A phase-accumulator-driven oscillator/noise source for `InputSource`'s
+/// generator variants.
+struct SignalGenerator {
+    source: InputSource,
+    sample_rate: u32,
+    phase: f32,
+    elapsed_frames: u64,
+    rng_state: u32,
+}
+
+impl SignalGenerator {
+    fn new(source: InputSource, sample_rate: u32) -> Self {
+        SignalGenerator {
+            source,
+            sample_rate,
+            phase: 0.0,
+            elapsed_frames: 0,
+            rng_state: 0x2545_f491,
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let sample = match self.source {
+            InputSource::Device => 0.0,
+            InputSource::Sine { freq } => self.next_sine(freq),
+            InputSource::WhiteNoise => self.next_noise(),
+            InputSource::Sweep { start, end, secs } => {
+                let t = self.elapsed_frames as f32 / self.sample_rate as f32;
+                let progress = (t / secs).min(1.0);
+                self.next_sine(start + (end - start) * progress)
+            }
+        };
+        self.elapsed_frames += 1;
+        sample
+    }
+
+    fn next_sine(&mut self, freq: f32) -> f32 {
+        let sample = self.phase.sin();
+        self.phase += 2.0 * std::f32::consts::PI * freq / self.sample_rate as f32;
+        if self.phase >= 2.0 * std::f32::consts::PI {
+            self.phase -= 2.0 * std::f32::consts::PI;
+        }
+        sample
+    }
+
+    /// xorshift32, good enough for a diagnostic noise source.
+    fn next_noise(&mut self) -> f32 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 17;
+        self.rng_state ^= self.rng_state << 5;
+        (self.rng_state as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+/// Drives the background thread that fills the ring buffer from a
+/// synthesized `InputSource` instead of a capture device, started when
+/// `Loopback::input_source` isn't `InputSource::Device`. Runs the same
+/// processing chain and recording tap as a real input stream would.
+struct Generator {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Generator {
+    fn spawn(
+        source: InputSource,
+        channels: usize,
+        sample_rate: u32,
+        mut producer: ringbuf::HeapProducer<f32>,
+        mut processors: Vec<Box<dyn Processor + Send>>,
+        mut record_producer: Option<ringbuf::HeapProducer<f32>>,
+        counters: Arc<XrunCounters>,
+    ) -> Self {
+        const BLOCK_FRAMES: usize = 256;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        let thread = std::thread::spawn(move || {
+            let mut generator = SignalGenerator::new(source, sample_rate);
+            let block_period = Duration::from_secs_f64(BLOCK_FRAMES as f64 / sample_rate as f64);
+            let mut block = vec![0.0f32; BLOCK_FRAMES * channels];
+
+            while !stop_thread.load(Ordering::Relaxed) {
+                for frame in block.chunks_mut(channels) {
+                    let sample = generator.next_sample();
+                    for s in frame.iter_mut() {
+                        *s = sample;
+                    }
+                }
+                for processor in processors.iter_mut() {
+                    processor.process(&mut block, channels, sample_rate);
+                }
+                for &sample in &block {
+                    if producer.push(sample).is_err() {
+                        counters.output_overruns.fetch_add(1, Ordering::Relaxed);
+                    }
+                    if let Some(record_producer) = record_producer.as_mut() {
+                        let _ = record_producer.push(sample);
+                    }
+                }
+                std::thread::sleep(block_period);
+            }
+        });
+        Generator { stop, thread: Some(thread) }
+    }
+}
+
+impl Drop for Generator {
+    /// Signals the background thread to stop and joins it. Runs whether the
+    /// generator is dropped explicitly (via `LoopbackHandle::stop`) or
+    /// implicitly, so the thread never keeps computing blocks and pushing
+    /// into a ring buffer nobody is reading from anymore.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if let Err(err) = thread.join() {
+                eprintln!("Generator thread panicked: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Owns the running input/output streams returned by [`Loopback::start`].
+/// The streams stay alive, and audio keeps flowing, for as long as this
+/// handle is held; drop it or call
+/// [`LoopbackHandle::stop`] to stop them.
+pub struct LoopbackHandle {
+    input_stream: Option<cpal::Stream>,
+    output_stream: Option<cpal::Stream>,
+    counters: Arc<XrunCounters>,
+    recorder: Option<Recorder>,
+    generator: Option<Generator>,
+}
+
+impl Drop for LoopbackHandle {
+    /// Tears down everything in an order that's safe whether this runs via
+    /// an explicit `stop()` or an implicit drop (early return, panic
+    /// unwind, or simply forgetting to call `stop()`): input production
+    /// (the capture stream, or the generator thread) stops first so
+    /// nothing is still producing samples, then the recorder drains and
+    /// finalizes, then playback stops. `Recorder` and `Generator` each have
+    /// their own `Drop` impl, so signaling/joining happens here regardless
+    /// of which path triggered it.
+    fn drop(&mut self) {
+        self.input_stream.take();
+        self.generator.take();
+        self.recorder.take();
+        self.output_stream.take();
+    }
+}
+
+impl LoopbackHandle {
+    /// Reads the current underrun/overrun counts without affecting the
+    /// running streams.
+    pub fn xruns(&self) -> XrunCounts {
+        XrunCounts {
+            output_overruns: self.counters.output_overruns.load(Ordering::Relaxed),
+            input_underruns: self.counters.input_underruns.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Stops both streams (or the generator thread, if the input was
+    /// synthesized) and, if recording was enabled, drains the remaining
+    /// buffered audio and finalizes the WAV file. Identical to letting the
+    /// handle drop; this just documents the intent at the call site.
+    pub fn stop(self) {}
+
+    /// Runs the loopback for a fixed duration, then stops it. This is the
+    /// old `start` behavior, now explicit at the call site.
+    pub fn run_for(self, duration: Duration) {
+        std::thread::sleep(duration);
+        self.stop();
+    }
+
+    /// Blocks until Ctrl-C (or another termination signal) is received,
+    /// then stops the streams.
+    pub fn run_forever(self) {
+        let running = Arc::new(AtomicBool::new(true));
+        let running_handler = running.clone();
+        if let Err(err) = ctrlc::set_handler(move || running_handler.store(false, Ordering::SeqCst)) {
+            eprintln!("Failed to install Ctrl-C handler: {err}");
+        }
+        while running.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        self.stop();
+    }
+}
+
+/// A single DSP stage applied to the interleaved signal in flight, between
+/// capture and the ring buffer.
+pub trait Processor {
+    fn process(&mut self, buffer: &mut [f32], channels: usize, sample_rate: u32);
+}
+
+/// Multiplies every sample by a fixed factor.
+pub struct Gain {
+    pub gain: f32,
+}
+
+impl Processor for Gain {
+    fn process(&mut self, buffer: &mut [f32], _channels: usize, _sample_rate: u32) {
+        for sample in buffer {
+            *sample *= self.gain;
+        }
+    }
+}
+
+/// A brick-wall limiter that clamps samples to `[-threshold, threshold]`.
+pub struct HardClip {
+    pub threshold: f32,
+}
+
+impl Processor for HardClip {
+    fn process(&mut self, buffer: &mut [f32], _channels: usize, _sample_rate: u32) {
+        for sample in buffer {
+            *sample = sample.clamp(-self.threshold, self.threshold);
+        }
+    }
+}
+
+/// A one-pole low-pass filter at `cutoff_hz`, with state tracked per
+/// channel.
+pub struct LowPass {
+    pub cutoff_hz: f32,
+    state: Vec<f32>,
+}
+
+impl LowPass {
+    pub fn new(cutoff_hz: f32) -> Self {
+        LowPass { cutoff_hz, state: Vec::new() }
+    }
+}
+
+impl Processor for LowPass {
+    fn process(&mut self, buffer: &mut [f32], channels: usize, sample_rate: u32) {
+        if self.state.len() != channels {
+            self.state = vec![0.0; channels];
+        }
+        let dt = 1.0 / sample_rate as f32;
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * self.cutoff_hz);
+        let alpha = dt / (rc + dt);
+        for frame in buffer.chunks_mut(channels) {
+            for (c, sample) in frame.iter_mut().enumerate() {
+                self.state[c] += alpha * (*sample - self.state[c]);
+                *sample = self.state[c];
+            }
+        }
+    }
+}
+
+/// A one-pole high-pass filter (the signal minus its low-passed component),
+/// with state tracked per channel.
+pub struct HighPass {
+    pub cutoff_hz: f32,
+    prev_in: Vec<f32>,
+    prev_out: Vec<f32>,
+}
+
+impl HighPass {
+    pub fn new(cutoff_hz: f32) -> Self {
+        HighPass { cutoff_hz, prev_in: Vec::new(), prev_out: Vec::new() }
+    }
+}
+
+impl Processor for HighPass {
+    fn process(&mut self, buffer: &mut [f32], channels: usize, sample_rate: u32) {
+        if self.prev_in.len() != channels {
+            self.prev_in = vec![0.0; channels];
+            self.prev_out = vec![0.0; channels];
+        }
+        let dt = 1.0 / sample_rate as f32;
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * self.cutoff_hz);
+        let alpha = rc / (rc + dt);
+        for frame in buffer.chunks_mut(channels) {
+            for (c, sample) in frame.iter_mut().enumerate() {
+                let x = *sample;
+                let y = alpha * (self.prev_out[c] + x - self.prev_in[c]);
+                self.prev_in[c] = x;
+                self.prev_out[c] = y;
+                *sample = y;
+            }
+        }
+    }
+}
+
+/// A fixed-delay feedback echo, backed by its own internal ring buffer so it
+/// can run independently of the input/output latency buffer.
+pub struct Echo {
+    pub feedback: f32,
+    pub mix: f32,
+    delay_samples: usize,
+    buffer: Vec<f32>,
+    channels: usize,
+    pos: usize,
+}
+
+impl Echo {
+    pub fn new(delay_samples: usize, feedback: f32, mix: f32) -> Self {
+        Echo {
+            feedback,
+            mix,
+            delay_samples: delay_samples.max(1),
+            buffer: Vec::new(),
+            channels: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl Processor for Echo {
+    fn process(&mut self, buffer: &mut [f32], channels: usize, _sample_rate: u32) {
+        if self.channels != channels {
+            // Round the delay up to a whole number of frames so each
+            // channel's tap stays aligned to the same channel instead of
+            // drifting across channels as `pos` cycles, mirroring how
+            // `LowPass`/`HighPass` lazily size their per-channel state.
+            let delay_frames = (self.delay_samples + channels - 1) / channels;
+            self.buffer = vec![0.0; delay_frames * channels];
+            self.channels = channels;
+            self.pos = 0;
+        }
+        for sample in buffer {
+            let delayed = self.buffer[self.pos];
+            self.buffer[self.pos] = *sample + delayed * self.feedback;
+            *sample += delayed * self.mix;
+            self.pos = (self.pos + 1) % self.buffer.len();
+        }
+    }
+}
+
+/// Converts one interleaved frame between channel counts: downmix by
+/// averaging, upmix by duplicating the last channel.
+fn convert_channels(frame: &[f32], in_channels: usize, out_channels: usize) -> Vec<f32> {
+    if in_channels == out_channels {
+        return frame.to_vec();
+    }
+    if out_channels < in_channels {
+        let avg = frame.iter().sum::<f32>() / in_channels as f32;
+        vec![avg; out_channels]
+    } else {
+        let mut out = Vec::with_capacity(out_channels);
+        out.extend_from_slice(frame);
+        let last = *frame.last().unwrap_or(&0.0);
+        out.resize(out_channels, last);
+        out
+    }
+}
+
+/// Linear/nearest resampling state for the consumer side of the ring
+/// buffer. `history` holds input frames already converted to
+/// `out_channels`, and `history_base` is the absolute frame index of
+/// `history[0]`, so a fractional read cursor can be kept into it.
+struct Resampler {
+    quality: ResampleQuality,
+    rate_ratio: f64,
+    in_channels: usize,
+    out_channels: usize,
+    history: Vec<f32>,
+    history_base: u64,
+    output_frame_n: u64,
+}
+
+impl Resampler {
+    fn new(quality: ResampleQuality, in_rate: f64, out_rate: f64, in_channels: usize, out_channels: usize) -> Self {
+        Resampler {
+            quality,
+            rate_ratio: in_rate / out_rate,
+            in_channels,
+            out_channels,
+            history: Vec::new(),
+            history_base: 0,
+            output_frame_n: 0,
+        }
+    }
+
+    /// Fills one `out_channels`-wide output frame, pulling and converting
+    /// input frames from `consumer` as needed. Returns `true` if the
+    /// consumer ran dry.
+    fn fill_frame(&mut self, consumer: &mut ringbuf::HeapConsumer<f32>, frame_out: &mut [f32]) -> bool {
+        let mut input_fell_behind = false;
+        let src = self.output_frame_n as f64 * self.rate_ratio;
+        let idx_a = src.floor() as u64;
+        let idx_b = idx_a + 1;
+        let frac = src.fract();
+
+        // Pull in enough input frames (converted to the output's channel
+        // count) to cover the upper bracketing sample `idx_b` -- unless
+        // `frac == 0.0`, in which case `b` is never actually read below, so
+        // requiring it would demand one frame more than the output needs
+        // and falsely flag `input_fell_behind` right as the consumer
+        // catches up.
+        let needed_idx = if frac > 0.0 { idx_b } else { idx_a };
+        while self.history_base + (self.history.len() / self.out_channels) as u64 <= needed_idx {
+            let mut in_frame = vec![0.0f32; self.in_channels];
+            for s in in_frame.iter_mut() {
+                *s = match consumer.pop() {
+                    Some(v) => v,
+                    None => {
+                        input_fell_behind = true;
+                        0.0
+                    }
+                };
+            }
+            self.history.extend(convert_channels(&in_frame, self.in_channels, self.out_channels));
+        }
+
+        // Drop frames we've fully consumed, keeping a little slack so
+        // `idx_a` always stays resolvable.
+        if idx_a > self.history_base + 4 {
+            let drop_frames = (idx_a - self.history_base - 4) as usize;
+            self.history.drain(0..drop_frames * self.out_channels);
+            self.history_base += drop_frames as u64;
+        }
+
+        let a_off = ((idx_a - self.history_base) as usize) * self.out_channels;
+        let b_off = ((idx_b - self.history_base) as usize) * self.out_channels;
+
+        for (c, sample) in frame_out.iter_mut().enumerate() {
+            let a = self.history[a_off + c];
+            let b = *self.history.get(b_off + c).unwrap_or(&a);
+            *sample = match self.quality {
+                ResampleQuality::Nearest => if frac < 0.5 { a } else { b },
+                ResampleQuality::Linear => a + (b - a) * frac as f32,
+            };
+        }
+
+        self.output_frame_n += 1;
+        input_fell_behind
+    }
+}
+
+/// Builds the capture stream for sample type `T`, running the processing
+/// chain and converting into f32 before pushing into the ring buffer.
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut producer: ringbuf::HeapProducer<f32>,
+    mut processors: Vec<Box<dyn Processor + Send>>,
+    channels: usize,
+    sample_rate: u32,
+    counters: Arc<XrunCounters>,
+    mut record_producer: Option<ringbuf::HeapProducer<f32>>,
+) -> anyhow::Result<cpal::Stream>
+where
+    T: cpal::SizedSample,
+    f32: cpal::FromSample<T>,
+{
+    let mut processed_block: Vec<f32> = Vec::new();
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            processed_block.clear();
+            processed_block.extend(data.iter().map(|&sample| f32::from_sample(sample)));
+            for processor in processors.iter_mut() {
+                processor.process(&mut processed_block, channels, sample_rate);
+            }
+
+            for &sample in &processed_block {
+                if producer.push(sample).is_err() {
+                    counters.output_overruns.fetch_add(1, Ordering::Relaxed);
+                }
+                if let Some(record_producer) = record_producer.as_mut() {
+                    // Dropped samples here only shorten the recording; they
+                    // never block or slow down the live loopback path.
+                    let _ = record_producer.push(sample);
+                }
+            }
+        },
+        err_fn,
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Builds the playback stream for sample type `T`, resampling out of the
+/// ring buffer in f32 and converting to `T` at the last moment.
+fn build_output_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut consumer: ringbuf::HeapConsumer<f32>,
+    mut resampler: Resampler,
+    channels: usize,
+    counters: Arc<XrunCounters>,
+) -> anyhow::Result<cpal::Stream>
+where
+    T: cpal::SizedSample + cpal::FromSample<f32>,
+{
+    let mut frame_buf = vec![0.0f32; channels];
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            for frame_out in data.chunks_mut(channels) {
+                if resampler.fill_frame(&mut consumer, &mut frame_buf) {
+                    counters.input_underruns.fetch_add(1, Ordering::Relaxed);
+                }
+                for (sample, &value) in frame_out.iter_mut().zip(frame_buf.iter()) {
+                    *sample = T::from_sample(value);
+                }
+            }
+        },
+        err_fn,
+        None,
+    )?;
+    Ok(stream)
+}
 
 fn err_fn(err: cpal::StreamError) {
     eprintln!("An error occurred on stream: {}", err);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring_with(samples: &[f32]) -> (ringbuf::HeapProducer<f32>, ringbuf::HeapConsumer<f32>) {
+        let ring = HeapRb::<f32>::new(samples.len() + 1);
+        let (mut producer, consumer) = ring.split();
+        for &sample in samples {
+            producer.push(sample).unwrap();
+        }
+        (producer, consumer)
+    }
+
+    #[test]
+    fn convert_channels_identity() {
+        assert_eq!(convert_channels(&[1.0, 2.0], 2, 2), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn convert_channels_downmix_averages() {
+        assert_eq!(convert_channels(&[1.0, 3.0], 2, 1), vec![2.0]);
+    }
+
+    #[test]
+    fn convert_channels_upmix_duplicates_last_channel() {
+        assert_eq!(convert_channels(&[1.0, 2.0], 2, 4), vec![1.0, 2.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn resampler_identity_rate_passes_samples_through() {
+        let (_producer, mut consumer) = ring_with(&[1.0, 2.0, 3.0, 4.0]);
+        let mut resampler = Resampler::new(ResampleQuality::Linear, 48_000.0, 48_000.0, 1, 1);
+        let mut out = [0.0f32];
+        for &expected in &[1.0, 2.0, 3.0, 4.0] {
+            assert!(!resampler.fill_frame(&mut consumer, &mut out));
+            assert_eq!(out[0], expected);
+        }
+    }
+
+    #[test]
+    fn resampler_downsamples_2_to_1_linearly() {
+        // in_rate = 2 * out_rate, so each output frame should land exactly
+        // on every other input sample.
+        let (_producer, mut consumer) = ring_with(&[0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+        let mut resampler = Resampler::new(ResampleQuality::Linear, 2.0, 1.0, 1, 1);
+        let mut out = [0.0f32];
+        for &expected in &[0.0, 2.0, 4.0] {
+            resampler.fill_frame(&mut consumer, &mut out);
+            assert_eq!(out[0], expected);
+        }
+    }
+
+    #[test]
+    fn resampler_nearest_quality_rounds_to_closer_sample() {
+        // in_rate / out_rate = 1.5, so the second output frame's source
+        // index is 1.5 -- exactly between input samples 1 and 2. Neither is
+        // closer, so the tie goes to the later sample, 2 (0.0).
+        let (_producer, mut consumer) = ring_with(&[0.0, 10.0, 0.0, 10.0]);
+        let mut resampler = Resampler::new(ResampleQuality::Nearest, 3.0, 2.0, 1, 1);
+        let mut out = [0.0f32];
+        resampler.fill_frame(&mut consumer, &mut out);
+        assert_eq!(out[0], 0.0);
+        resampler.fill_frame(&mut consumer, &mut out);
+        assert_eq!(out[0], 0.0);
+    }
+
+    #[test]
+    fn resampler_upmixes_mono_input_to_stereo_output() {
+        let (_producer, mut consumer) = ring_with(&[1.0, 2.0]);
+        let mut resampler = Resampler::new(ResampleQuality::Linear, 1.0, 1.0, 1, 2);
+        let mut out = [0.0f32; 2];
+        resampler.fill_frame(&mut consumer, &mut out);
+        assert_eq!(out, [1.0, 1.0]);
+    }
+
+    #[test]
+    fn gain_scales_every_sample() {
+        let mut gain = Gain { gain: 2.0 };
+        let mut buf = [1.0, -2.0, 0.5];
+        gain.process(&mut buf, 1, 48_000);
+        assert_eq!(buf, [2.0, -4.0, 1.0]);
+    }
+
+    #[test]
+    fn hard_clip_clamps_to_threshold_and_leaves_smaller_samples_alone() {
+        let mut clip = HardClip { threshold: 0.5 };
+        let mut buf = [0.3, -0.3, 0.9, -0.9, 0.5, -0.5];
+        clip.process(&mut buf, 1, 48_000);
+        assert_eq!(buf, [0.3, -0.3, 0.5, -0.5, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn low_pass_converges_to_a_constant_input() {
+        let mut lp = LowPass::new(200.0);
+        let mut last = 0.0;
+        for _ in 0..1000 {
+            let mut buf = [1.0];
+            lp.process(&mut buf, 1, 48_000);
+            last = buf[0];
+        }
+        assert!((last - 1.0).abs() < 0.01, "expected convergence near 1.0, got {last}");
+    }
+
+    #[test]
+    fn low_pass_attenuates_a_single_sample_impulse() {
+        let mut lp = LowPass::new(200.0);
+        let mut buf = [1.0];
+        lp.process(&mut buf, 1, 48_000);
+        assert!(buf[0] < 0.5, "expected the impulse to be attenuated, got {}", buf[0]);
+    }
+
+    #[test]
+    fn high_pass_decays_a_constant_input_toward_zero() {
+        let mut hp = HighPass::new(200.0);
+        let mut last = 1.0;
+        for _ in 0..5000 {
+            let mut buf = [1.0];
+            hp.process(&mut buf, 1, 48_000);
+            last = buf[0];
+        }
+        assert!(last.abs() < 0.01, "expected DC to decay toward 0, got {last}");
+    }
+
+    #[test]
+    fn echo_keeps_channels_independent() {
+        // `delay_samples` isn't a multiple of `channels`, which used to
+        // bleed the left channel's echo into the right one; it should now
+        // get rounded up to a whole number of stereo frames instead.
+        let mut echo = Echo::new(3, 0.0, 1.0);
+        let channels = 2;
+
+        let mut buf = vec![0.0f32; 8];
+        buf[0] = 1.0; // impulse on the left channel of frame 0
+        echo.process(&mut buf, channels, 48_000);
+
+        let mut buf2 = vec![0.0f32; 8];
+        echo.process(&mut buf2, channels, 48_000);
+
+        for frame in buf.chunks(channels).chain(buf2.chunks(channels)) {
+            assert_eq!(frame[1], 0.0, "right channel must never pick up the left channel's echo");
+        }
+        // The left channel's echo does reappear, still on the left channel.
+        assert!(buf.iter().step_by(2).skip(1).any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn signal_generator_sine_phase_wraps_at_2pi() {
+        let mut gen = SignalGenerator::new(InputSource::Sine { freq: 1000.0 }, 48_000);
+        for _ in 0..48_000 {
+            gen.next_sample();
+        }
+        assert!(gen.phase >= 0.0 && gen.phase < 2.0 * std::f32::consts::PI);
+    }
+
+    #[test]
+    fn signal_generator_white_noise_stays_in_range() {
+        let mut gen = SignalGenerator::new(InputSource::WhiteNoise, 48_000);
+        for _ in 0..10_000 {
+            let sample = gen.next_sample();
+            assert!((-1.0..=1.0).contains(&sample), "noise sample {sample} out of range");
+        }
+    }
+
+    #[test]
+    fn signal_generator_sweep_holds_at_end_after_duration() {
+        let mut gen = SignalGenerator::new(InputSource::Sweep { start: 100.0, end: 200.0, secs: 0.01 }, 48_000);
+        // Run well past `secs` so the sweep's progress has clamped to 1.0.
+        for _ in 0..48_000 {
+            gen.next_sample();
+        }
+        let held_freq_phase_step = gen.phase;
+        gen.next_sample();
+        let expected_step = 2.0 * std::f32::consts::PI * 200.0 / 48_000.0;
+        let actual_step = (gen.phase - held_freq_phase_step + 2.0 * std::f32::consts::PI) % (2.0 * std::f32::consts::PI);
+        assert!((actual_step - expected_step).abs() < 1e-4);
+    }
+}